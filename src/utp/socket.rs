@@ -2,10 +2,10 @@
 use async_std::net::{UdpSocket, SocketAddr};
 use async_std::io::{ErrorKind, Error};
 use rand::Rng;
+use socket2::{Socket, Domain, Type, Protocol};
 
 use std::time::{Duration, Instant};
-use std::{iter::Iterator, collections::VecDeque};
-use std::iter;
+use std::{iter::Iterator, collections::VecDeque, collections::HashMap, collections::HashSet, collections::BTreeMap};
 
 use super::{
     ConnectionId, Result, UtpError, Packet, PacketRef, PacketType,
@@ -56,6 +56,285 @@ const TARGET: u32 = 100_000; //100;
 const GAIN: u32 = 1;
 const ALLOWED_INCREASE: u32 = 1;
 
+/// RFC 6298 clock granularity (G): lower bound on the variance term
+/// (`max(G, 4*RTTVAR)`) added to SRTT.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(100);
+const MIN_CONGESTION_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_CONGESTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// uTP extension type carrying a Selective ACK bitmask
+const SACK_EXTENSION: u8 = 1;
+/// A hole is fast-retransmitted once this many SACK acks have reported it missing
+const SACK_FAST_RETRANSMIT_THRESHOLD: u8 = 3;
+/// Give up the connection once the oldest inflight packet has been resent this many times
+const MAX_RETRANSMISSION_RETRIES: u32 = 5;
+/// Advertised receive window, shrunk as the reassembly buffer fills up
+const WINDOW_SIZE: u32 = 1_048_576;
+
+/// uTP extension type for a throwaway DPLPMTUD probe; its padding is
+/// discarded by the receiver rather than delivered to the application.
+const MTU_PROBE_EXTENSION: u8 = 2;
+/// Conservative starting payload size, per RFC 8899
+const PMTU_BASE: usize = 1200;
+/// How much bigger each probed candidate is than the current working size
+const PMTU_PROBE_STEP: usize = 200;
+/// Minimum gap between probes of a larger size
+const PMTU_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for a probe's ack before treating it as lost
+const PMTU_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Unacked attempts at a candidate size before giving up on it
+const PMTU_PROBE_MAX_RETRIES: u32 = 3;
+/// Consecutive congestion timeouts at the current size taken as a black hole
+const PMTU_BLACKHOLE_THRESHOLD: u32 = 3;
+
+/// Datagram Packetization Layer PMTU Discovery (RFC 8899): starts from a
+/// conservative base and probes upward, capping the ceiling just below any
+/// size that repeatedly goes unacked. Probes carry their own identifier
+/// (`next_probe_id`) instead of a data stream seq number, so a lost probe
+/// never leaves a gap the reassembly buffer has to wait on forever — it's
+/// tracked, and can time out, entirely independently of `inflight_packets`.
+#[derive(Debug)]
+struct PmtuDiscovery {
+    /// Payload size currently safe to chunk real data into
+    current: usize,
+    /// Highest payload size not yet proven unreachable; never probe above it
+    ceiling: usize,
+    /// (candidate size, the probe's id, when it was sent, attempt count)
+    probe: Option<(usize, u32, Instant, u32)>,
+    last_probe_at: Option<Instant>,
+    consecutive_timeouts: u32,
+    /// Counter handed out to identify each outstanding probe, independent of
+    /// the connection's data seq_number.
+    next_probe_id: u32,
+}
+
+impl PmtuDiscovery {
+    fn new(ceiling: usize) -> PmtuDiscovery {
+        PmtuDiscovery {
+            current: PMTU_BASE.min(ceiling),
+            ceiling,
+            probe: None,
+            last_probe_at: None,
+            consecutive_timeouts: 0,
+            next_probe_id: 0,
+        }
+    }
+
+    fn current_size(&self) -> usize {
+        self.current
+    }
+
+    fn should_probe(&self, now: Instant) -> bool {
+        if self.probe.is_some() || self.current >= self.ceiling {
+            return false;
+        }
+
+        match self.last_probe_at {
+            Some(last) => now.duration_since(last) >= PMTU_PROBE_INTERVAL,
+            None => true,
+        }
+    }
+
+    fn next_probe_size(&self) -> usize {
+        (self.current + PMTU_PROBE_STEP).min(self.ceiling)
+    }
+
+    fn next_probe_id(&mut self) -> u32 {
+        self.next_probe_id = self.next_probe_id.wrapping_add(1);
+        self.next_probe_id
+    }
+
+    fn probe_sent(&mut self, size: usize, probe_id: u32, now: Instant) {
+        self.probe = Some((size, probe_id, now, 1));
+        self.last_probe_at = Some(now);
+    }
+
+    /// If the outstanding probe has gone unanswered for `PMTU_PROBE_TIMEOUT`,
+    /// either hand back `(size, probe_id)` for the caller to actually resend
+    /// on the wire with a fresh timestamp, or — once `PMTU_PROBE_MAX_RETRIES`
+    /// real attempts have all gone unanswered — give up and cap the ceiling
+    /// just below the size that failed. Returns `None` if there's nothing
+    /// outstanding or it hasn't timed out yet.
+    fn check_probe_timeout(&mut self, now: Instant) -> Option<(usize, u32)> {
+        let (size, probe_id, sent_at, attempts) = self.probe?;
+
+        if now.duration_since(sent_at) < PMTU_PROBE_TIMEOUT {
+            return None;
+        }
+
+        if attempts >= PMTU_PROBE_MAX_RETRIES {
+            self.ceiling = size.saturating_sub(1);
+            self.probe = None;
+            return None;
+        }
+
+        self.probe = Some((size, probe_id, now, attempts + 1));
+        Some((size, probe_id))
+    }
+
+    /// A (possibly duplicate) ack arrived, meaning the connection isn't
+    /// stalled; this is independent of whether it also confirms a probe.
+    fn on_ack_received(&mut self) {
+        self.consecutive_timeouts = 0;
+    }
+
+    /// The peer echoed back `probe_id`, confirming the candidate size got
+    /// through the path intact.
+    fn on_probe_acked(&mut self, probe_id: u32) {
+        if let Some((size, id, ..)) = self.probe {
+            if id == probe_id {
+                self.current = size;
+                self.probe = None;
+            }
+        }
+    }
+
+    /// A congestion timeout fired; sustained loss at a size that was
+    /// working is treated as a black hole, not ordinary congestion.
+    fn on_timeout(&mut self) {
+        self.consecutive_timeouts += 1;
+
+        if self.consecutive_timeouts >= PMTU_BLACKHOLE_THRESHOLD {
+            self.current = PMTU_BASE.min(self.ceiling);
+            self.probe = None;
+            self.consecutive_timeouts = 0;
+        }
+    }
+}
+
+/// A pluggable congestion window algorithm. uTP defaults to the delay-based
+/// `Ledbat` so it yields to TCP traffic sharing the path, but a socket can
+/// be configured to use the loss-based `Cubic` instead when the one-way
+/// delay signal can't be trusted (e.g. a relay reshaping timestamps).
+pub trait CongestionControl: std::fmt::Debug + Send {
+    /// A received ack newly acknowledged `bytes_acked` bytes. `inflight` is
+    /// the number of bytes still outstanding, including the packets this
+    /// ack just cleared (they're popped after this call returns), and
+    /// `delay` is the raw one-way delay sample (timestamp difference)
+    /// carried by the ack, zero if unavailable; it still includes the base
+    /// delay, which `Ledbat` subtracts to get the queuing delay.
+    fn on_ack(&mut self, bytes_acked: u32, inflight: u32, delay: Delay);
+    /// A loss was detected out of band, e.g. by a fast retransmit.
+    fn on_loss(&mut self);
+    /// The congestion timeout fired with no ack received in time.
+    fn on_timeout(&mut self);
+    /// The current congestion window, in bytes.
+    fn window(&self) -> u32;
+}
+
+/// The standard LEDBAT algorithm: grows or shrinks `cwnd` to keep the
+/// measured one-way queuing delay near `TARGET`, so it backs off before a
+/// shared bottleneck queue ever builds up enough to hurt TCP.
+#[derive(Debug)]
+pub struct Ledbat {
+    cwnd: u32,
+    delay_history: DelayHistory,
+}
+
+impl Ledbat {
+    pub fn new() -> Ledbat {
+        Ledbat {
+            cwnd: INIT_CWND * MSS,
+            delay_history: DelayHistory::new(),
+        }
+    }
+}
+
+impl CongestionControl for Ledbat {
+    fn on_ack(&mut self, bytes_acked: u32, inflight: u32, delay: Delay) {
+        if !delay.is_zero() {
+            self.delay_history.add_delay(delay);
+        }
+
+        let queuing_delay = self.delay_history.filtered_current_delay() - self.delay_history.base_delay();
+        let queuing_delay: i64 = queuing_delay.into();
+
+        let off_target = (TARGET as f64 - queuing_delay as f64) / TARGET as f64;
+
+        let cwnd = self.cwnd as f64
+            + (GAIN as f64 * off_target * bytes_acked as f64 * MSS as f64) / self.cwnd as f64;
+
+        let max_allowed_cwnd = inflight + ALLOWED_INCREASE * MSS;
+
+        self.cwnd = (cwnd as u32).min(max_allowed_cwnd).max(MIN_CWND * MSS);
+    }
+
+    fn on_loss(&mut self) {
+        let cwnd = self.cwnd;
+        self.cwnd = cwnd.min((cwnd / 2).max(MIN_CWND * MSS));
+    }
+
+    fn on_timeout(&mut self) {
+        self.cwnd = MSS;
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+}
+
+/// CUBIC window ratio at the last loss event (beta)
+const CUBIC_BETA: f64 = 0.7;
+/// CUBIC scaling constant
+const CUBIC_C: f64 = 0.4;
+
+/// Loss-based CUBIC, for when the delay signal LEDBAT relies on isn't
+/// reliable. Growth follows the cubic function of time since the last
+/// loss, floored by a Reno-equivalent estimate so it stays TCP-friendly.
+#[derive(Debug)]
+pub struct Cubic {
+    cwnd: u32,
+    /// cwnd at the time of the last loss, the curve's inflection point
+    w_max: u32,
+    /// Start of the current growth epoch: the last loss, or construction
+    /// time if there hasn't been one yet, so the curve starts growing
+    /// immediately instead of sitting at `t=0` until a first loss ever
+    /// happens (which it otherwise never would, since cwnd never grows).
+    last_loss: Instant,
+}
+
+impl Cubic {
+    pub fn new() -> Cubic {
+        Cubic {
+            cwnd: INIT_CWND * MSS,
+            w_max: INIT_CWND * MSS,
+            last_loss: Instant::now(),
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(&mut self, _bytes_acked: u32, _inflight: u32, _delay: Delay) {
+        let t = self.last_loss.elapsed().as_secs_f64();
+
+        let w_max = self.w_max as f64 / MSS as f64;
+        let k = (w_max * (1.0 - CUBIC_BETA) / CUBIC_C).cbrt();
+
+        let cubic_cwnd = CUBIC_C * (t - k).powi(3) + w_max;
+        // TCP-friendly region: never fall below what Reno would have reached.
+        let reno_cwnd = w_max * CUBIC_BETA + t / (w_max * CUBIC_BETA);
+
+        let cwnd_segments = cubic_cwnd.max(reno_cwnd);
+
+        self.cwnd = ((cwnd_segments * MSS as f64) as u32).max(MIN_CWND * MSS);
+    }
+
+    fn on_loss(&mut self) {
+        self.w_max = self.cwnd;
+        self.cwnd = ((self.cwnd as f64 * CUBIC_BETA) as u32).max(MIN_CWND * MSS);
+        self.last_loss = Instant::now();
+    }
+
+    fn on_timeout(&mut self) {
+        self.cwnd = MSS;
+        self.last_loss = Instant::now();
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+}
+
 pub struct UtpSocket {
     local: SocketAddr,
     remote: Option<SocketAddr>,
@@ -74,53 +353,77 @@ pub struct UtpSocket {
     /// Packets sent but we didn't receive an ack for them
     inflight_packets: VecDeque<Packet>,
 
-    // base_delays: VecDeque<Delay>,
-
-    // current_delays: VecDeque<Delay>, // TODO: Use SliceDeque ?
-
-    // last_rollover: Instant,
-
-    // flight_size: u32,
-
-    delay_history: DelayHistory,
-
-    cwnd: u32,
+    congestion: Box<dyn CongestionControl>,
     congestion_timeout: Duration,
 
-    // /// SRTT (smoothed round-trip time)
-    // srtt: u32,
-    // /// RTTVAR (round-trip time variation)
-    // rttvar: u32,
+    /// SRTT (smoothed round-trip time), not yet seeded until the first sample
+    srtt: Option<Duration>,
+    /// RTTVAR (round-trip time variation)
+    rttvar: Duration,
+
+    /// Number of SACK acks that have reported each still-missing seq as a hole
+    /// below a sacked packet
+    sack_miss_counts: HashMap<SequenceNumber, u8>,
+    /// Last time a fast retransmit raised `on_data_loss`, to cap it to once per RTT
+    last_loss_signal: Option<Instant>,
+
+    /// Out-of-order data seq numbers currently buffered on the receive side,
+    /// reported back to the sender as a SACK extension on our next ack.
+    out_of_order_seqs: Vec<SequenceNumber>,
+
+    /// In-order bytes ready for the application to read via `recv`
+    recv_buffer: VecDeque<u8>,
+    /// Data packets that arrived ahead of `ack_number`, keyed by seq number;
+    /// merged into `recv_buffer` as the gaps before them fill in.
+    held_segments: BTreeMap<SequenceNumber, Vec<u8>>,
+
+    /// Path MTU discovery state, driving the payload size used by `send`
+    pmtu: PmtuDiscovery,
+    /// Id of the most recently received MTU probe, echoed back on our next
+    /// ack to confirm it arrived; unrelated to `ack_number`.
+    pending_probe_ack: Option<u32>,
 }
 
 impl UtpSocket {
     fn new(local: SocketAddr, udp: UdpSocket) -> UtpSocket {
-        let (recv_id, send_id) = ConnectionId::make_ids();
+        Self::with_congestion_control(local, udp, Box::new(Ledbat::new()))
+    }
 
-        // let mut base_delays = VecDeque::with_capacity(BASE_HISTORY);
-        // base_delays.extend(iter::repeat(Delay::infinity()).take(BASE_HISTORY));
+    fn with_congestion_control(
+        local: SocketAddr,
+        udp: UdpSocket,
+        congestion: Box<dyn CongestionControl>,
+    ) -> UtpSocket {
+        let (recv_id, send_id) = ConnectionId::make_ids();
+        let mtu_ceiling = if local.is_ipv4() {
+            UDP_IPV4_MTU - HEADER_SIZE
+        } else {
+            UDP_IPV6_MTU - HEADER_SIZE
+        };
 
         UtpSocket {
             local,
             udp,
             recv_id,
             send_id,
-            // base_delays,
             remote: None,
             state: State::None,
             ack_number: SequenceNumber::zero(),
             seq_number: SequenceNumber::random(),
             delay: Delay::default(),
-            // current_delays: VecDeque::with_capacity(16),
-            // last_rollover: Instant::now(),
-            cwnd: INIT_CWND * MSS,
+            congestion,
             congestion_timeout: Duration::from_secs(1),
-            // flight_size: 0,
-            // srtt: 0,
-            // rttvar: 0,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            sack_miss_counts: HashMap::new(),
+            last_loss_signal: None,
+            out_of_order_seqs: Vec::new(),
+            recv_buffer: VecDeque::new(),
+            held_segments: BTreeMap::new(),
             inflight_packets: VecDeque::with_capacity(64),
             remote_window: INIT_CWND * MSS,
-            delay_history: DelayHistory::new(),
+            pmtu: PmtuDiscovery::new(mtu_ceiling),
+            pending_probe_ack: None,
         }
     }
 
@@ -130,6 +433,18 @@ impl UtpSocket {
         Ok(Self::new(addr, udp))
     }
 
+    /// Same as `bind`, but with an explicit congestion control algorithm
+    /// instead of the default LEDBAT (e.g. `Cubic` behind a relay that
+    /// reshapes timestamps and makes the one-way delay signal unreliable).
+    pub async fn bind_with_congestion_control(
+        addr: SocketAddr,
+        congestion: Box<dyn CongestionControl>,
+    ) -> Result<UtpSocket> {
+        let udp = UdpSocket::bind(addr).await?;
+
+        Ok(Self::with_congestion_control(addr, udp, congestion))
+    }
+
     /// Addr must match the ip familly of the bind address (ipv4 / ipv6)
     pub async fn connect(&mut self, addr: SocketAddr) -> Result<()> {
         if addr.is_ipv4() != self.local.is_ipv4() {
@@ -144,7 +459,7 @@ impl UtpSocket {
         let mut header = Header::new(PacketType::Syn);
         header.set_connection_id(self.recv_id);
         header.set_seq_number(self.seq_number);
-        header.set_window_size(1_048_576);
+        header.set_window_size(self.advertised_window());
         self.seq_number += 1;
 
         for _ in 0..3 {
@@ -180,6 +495,8 @@ impl UtpSocket {
     }
 
     pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.maybe_probe_pmtu().await?;
+
         let packet_size = self.packet_size();
         let packets = data.chunks(packet_size).map(Packet::new);
 
@@ -190,41 +507,57 @@ impl UtpSocket {
         self.wait_for_reception().await
     }
 
-    async fn wait_for_reception(&mut self) -> Result<()> {
-        let last_seq = self.seq_number - 1;
+    /// Drain up to `buf.len()` in-order bytes from the reassembly buffer,
+    /// waiting on the wire for more data if none is ready yet.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.recv_buffer.is_empty() {
+            if self.state != State::Connected {
+                return Ok(0);
+            }
+
+            self.receive_packet().await?;
+        }
+
+        let n = buf.len().min(self.recv_buffer.len());
 
-        let mut is_last_acked = self.is_packet_acked(last_seq);
+        for (dst, src) in buf[..n].iter_mut().zip(self.recv_buffer.drain(..n)) {
+            *dst = src;
+        }
 
-        while !is_last_acked {
+        Ok(n)
+    }
+
+    /// Block until every packet from the last `send()` has actually been
+    /// acked. `inflight_packets` only loses an entry once SACK or a
+    /// cumulative ack covers it (`handle_sack`/`handle_state`), so waiting
+    /// for it to empty out — rather than just checking the last seq number
+    /// sent — still catches an earlier hole SACKed around by later data.
+    async fn wait_for_reception(&mut self) -> Result<()> {
+        while !self.inflight_packets.is_empty() {
             println!("LOOP IS ACKED", );
             self.receive_packet().await?;
-            is_last_acked = self.is_packet_acked(last_seq);
         }
 
         Ok(())
     }
 
-    fn is_packet_acked(&self, n: SequenceNumber) -> bool {
-        !self.inflight_packets.iter().any(|p| p.get_seq_number() == n)
-    }
-
     async fn send_packet(&mut self, mut packet: Packet) -> Result<()> {
 
         let packet_size = packet.size();
         let mut inflight_size = self.inflight_size();
-        let mut window = self.cwnd.min(self.remote_window) as usize;
+        let mut window = self.congestion.window().min(self.remote_window) as usize;
 
         while packet_size + inflight_size > window {
             self.receive_packet().await?;
 
             inflight_size = self.inflight_size();
-            window = self.cwnd.min(self.remote_window) as usize;
+            window = self.congestion.window().min(self.remote_window) as usize;
         }
 
         packet.set_ack_number(self.ack_number);
         packet.set_seq_number(self.seq_number);
         packet.set_connection_id(self.send_id);
-        packet.set_window_size(1_048_576);
+        packet.set_window_size(self.advertised_window());
         self.seq_number += 1;
         packet.update_timestamp();
 
@@ -232,6 +565,7 @@ impl UtpSocket {
 
         self.udp.send(packet.as_bytes()).await?;
 
+        packet.set_send_time(Instant::now());
         self.inflight_packets.push_back(packet);
 
         Ok(())
@@ -240,32 +574,51 @@ impl UtpSocket {
     async fn receive_packet(&mut self) -> Result<()> {
         let mut buffer = [0; 1500];
 
-        let mut timeout = self.congestion_timeout;
-        let mut len = None;
-
-        for _ in 0..3 {
-            match self.udp.recv_timeout(&mut buffer, timeout).await {
+        loop {
+            match self.udp.recv_timeout(&mut buffer, self.congestion_timeout).await {
                 Ok(n) => {
-                    len = Some(n);
-                    break;
+                    let packet = PacketRef::ref_from_buffer(&buffer[..n])?;
+                    return self.dispatch(packet).await;
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                    timeout *= 2;
-                    continue;
+                    self.retransmit_oldest().await?;
                 }
                 Err(e) => {
                     return Err(e.into());
                 }
             }
         }
+    }
 
-        if let Some(len) = len {
-            let packet = PacketRef::ref_from_buffer(&buffer[..len])?;
-            self.dispatch(packet).await?;
+    /// Resend the oldest unacked packet after a congestion timeout fires,
+    /// giving up the connection once it's been retried too many times.
+    async fn retransmit_oldest(&mut self) -> Result<()> {
+        if self.inflight_packets.is_empty() {
+            // Nothing inflight, the timeout was stale; no congestion response needed.
             return Ok(());
-        };
+        }
+
+        self.on_congestion_timeout_expired();
+
+        let oldest = self.inflight_packets.front_mut().expect("checked non-empty above");
+
+        if oldest.get_retries() >= MAX_RETRANSMISSION_RETRIES {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "max retransmission retries exceeded",
+            ).into());
+        }
+
+        oldest.set_retries(oldest.get_retries() + 1);
+        oldest.set_retransmission(true);
+        oldest.update_timestamp();
 
-        Err(Error::new(ErrorKind::TimedOut, "timed out").into())
+        let bytes = oldest.as_bytes().to_vec();
+        self.udp.send(&bytes).await?;
+
+        self.inflight_packets.front_mut().expect("checked non-empty above").set_send_time(Instant::now());
+
+        Ok(())
     }
 
     /// Returns the number of bytes currently in flight (sent but not acked)
@@ -273,15 +626,109 @@ impl UtpSocket {
         self.inflight_packets.iter().map(Packet::size).sum()
     }
 
+    /// Window we advertise to the remote: WINDOW_SIZE minus whatever the
+    /// reassembly buffer is already holding, so a slow reader throttles the
+    /// sender instead of having us drop data on the floor.
+    fn advertised_window(&self) -> u32 {
+        let buffered: usize = self.recv_buffer.len()
+            + self.held_segments.values().map(Vec::len).sum::<usize>();
+
+        WINDOW_SIZE.saturating_sub(buffered as u32)
+    }
+
+    /// Accept a Data packet into the reassembly buffer: in-order bytes go
+    /// straight to `recv_buffer`, anything ahead of a gap is held until the
+    /// gap fills, merging contiguous held segments as they connect.
+    fn handle_data(&mut self, packet: &PacketRef<'_>) {
+        // A PMTU probe rides its own id in the extension payload rather than
+        // the data stream's seq_number, so it never enters the reassembly
+        // pipeline at all: a lost probe can't leave a gap for later data to
+        // wait on forever. Just remember its id to echo back on our next ack.
+        if let Some(id_bytes) = packet.get_extension(MTU_PROBE_EXTENSION) {
+            if let Ok(id_bytes) = <[u8; 4]>::try_from(id_bytes) {
+                self.pending_probe_ack = Some(u32::from_be_bytes(id_bytes));
+            }
+
+            return;
+        }
+
+        let seq = packet.get_seq_number();
+
+        if seq.cmp_less_equal(self.ack_number) {
+            // Already delivered, this is a retransmitted duplicate.
+            return;
+        }
+
+        let payload = packet.payload().to_vec();
+
+        if payload.is_empty() {
+            return;
+        }
+
+        if seq != self.ack_number + 1 {
+            self.held_segments.entry(seq).or_insert(payload);
+
+            if !self.out_of_order_seqs.contains(&seq) {
+                self.out_of_order_seqs.push(seq);
+            }
+
+            return;
+        }
+
+        self.recv_buffer.extend(payload);
+        self.ack_number = seq;
+
+        while let Some(next) = self.held_segments.remove(&(self.ack_number + 1)) {
+            self.ack_number += 1;
+            self.recv_buffer.extend(next);
+            self.out_of_order_seqs.retain(|&s| s != self.ack_number);
+        }
+    }
+
     fn packet_size(&self) -> usize {
-        let is_ipv4 = self.remote.map(|r| r.is_ipv4()).unwrap_or(true);
+        self.pmtu.current_size()
+    }
 
-        // TODO: Change this when MTU discovery is implemented
-        if is_ipv4 {
-            UDP_IPV4_MTU - HEADER_SIZE
-        } else {
-            UDP_IPV6_MTU - HEADER_SIZE
+    /// Resend a timed-out probe, or opportunistically try a larger size (at
+    /// most once per `PMTU_PROBE_INTERVAL`, only while one isn't already
+    /// outstanding). `PMTU_PROBE_MAX_RETRIES` only actually bounds the number
+    /// of lost probes this way, since each timeout here puts a fresh copy on
+    /// the wire rather than just ticking an in-memory counter.
+    async fn maybe_probe_pmtu(&mut self) -> Result<()> {
+        if let Some((size, probe_id)) = self.pmtu.check_probe_timeout(Instant::now()) {
+            return self.send_probe(size, probe_id).await;
+        }
+
+        if !self.pmtu.should_probe(Instant::now()) {
+            return Ok(());
         }
+
+        let size = self.pmtu.next_probe_size();
+        let probe_id = self.pmtu.next_probe_id();
+
+        self.send_probe(size, probe_id).await?;
+        self.pmtu.probe_sent(size, probe_id, Instant::now());
+
+        Ok(())
+    }
+
+    /// Put an MTU probe of `size` bytes, identified by `probe_id`, on the
+    /// wire. The probe's seq_number is never read by the receive-side
+    /// reassembly logic (`handle_data` short-circuits on the extension
+    /// before looking at it), so it's left unconsumed rather than taken out
+    /// of the data stream's own numbering.
+    async fn send_probe(&mut self, size: usize, probe_id: u32) -> Result<()> {
+        let mut packet = Packet::new(&vec![0u8; size]);
+        packet.set_ack_number(self.ack_number);
+        packet.set_seq_number(self.seq_number);
+        packet.set_connection_id(self.send_id);
+        packet.set_window_size(self.advertised_window());
+        packet.set_extension(MTU_PROBE_EXTENSION, &probe_id.to_be_bytes());
+        packet.update_timestamp();
+
+        self.udp.send(packet.as_bytes()).await?;
+
+        Ok(())
     }
 
     async fn dispatch(&mut self, packet: PacketRef<'_>) -> Result<()> {
@@ -312,20 +759,19 @@ impl UtpSocket {
                 println!("CONNECTED !", );
             }
             (PacketType::State, State::Connected) => {
-                self.handle_state(packet);
-                // let current_delay = packet.get_timestamp_diff();
-                // let base_delay = std::cmp::min();
-                // current_delay = acknowledgement.delay
-                // base_delay = min(base_delay, current_delay)
-                // queuing_delay = current_delay - base_delay
-                // off_target = (TARGET - queuing_delay) / TARGET
-                // cwnd += GAIN * off_target * bytes_newly_acked * MSS / cwnd
-                // Ack received
+                self.handle_state(&packet);
+                self.handle_sack(&packet).await?;
+                self.handle_probe_ack(&packet);
             }
             (PacketType::State, _) => {
                 // Wrong Packet
             }
+            (PacketType::Data, State::Connected) => {
+                self.handle_data(&packet);
+                self.send_ack().await?;
+            }
             (PacketType::Data, _) => {
+                // Wrong Packet
             }
             (PacketType::Fin, _) => {
             }
@@ -336,154 +782,165 @@ impl UtpSocket {
         Ok(())
     }
 
-    // fn update_base_delay(&mut self, delay: Delay) {
-    //     // # Maintain BASE_HISTORY delay-minima.
-    //     // # Each minimum is measured over a period of a minute.
-    //     // # 'now' is the current system time
-    //     // if round_to_minute(now) != round_to_minute(last_rollover)
-    //     //     last_rollover = now
-    //     //     delete first item in base_delays list
-    //     //     append delay to base_delays list
-    //     // else
-    //     //     base_delays.tail = MIN(base_delays.tail, delay)
-    //     if self.last_rollover.elapsed() >= Duration::from_secs(1) {
-    //         self.last_rollover = Instant::now();
-    //         self.base_delays.pop_front();
-    //         self.base_delays.push_back(delay);
-    //     } else {
-    //         let last = self.base_delays.pop_back().unwrap();
-    //         self.base_delays.push_back(last.min(delay));
-    //     }
-    // }
-
-    // fn update_current_delay(&mut self, delay: Delay) {
-    //     //  # Maintain a list of CURRENT_FILTER last delays observed.
-    //     // delete first item in current_delays list
-    //     // append delay to current_delays list
-
-    //     // TODO: Pop delays before the last RTT
-    //     self.current_delays.pop_front();
-    //     self.current_delays.push_back(delay);
-    // }
-
-    // fn filter_current_delays(&self) -> Delay {
-    //     // TODO: Test other algos
-
-    //     // We're using the exponentially weighted moving average (EWMA) function
-    //     // Magic number from https://github.com/VividCortex/ewma
-    //     let alpha = 0.032_786_885;
-    //     let mut samples = self.current_delays.iter().map(|d| d.as_num() as f64);
-    //     let first = samples.next().unwrap_or(0.0);
-    //     (samples.fold(
-    //         first,
-    //         |acc, delay| alpha * delay + (acc * (1.0 - alpha))
-    //     ) as i64).into()
-    // }
-
     fn on_data_loss(&mut self) {
-        // on data loss:
-        // # at most once per RTT
-        // cwnd = min (cwnd, max (cwnd/2, MIN_CWND * MSS))
-        // if data lost is not to be retransmitted:
-        //     flightsize = flightsize - bytes_not_to_be_retransmitted
-        let cwnd = self.cwnd;
-        self.cwnd = cwnd.min((cwnd / 2).max(MIN_CWND * MSS));
-        // TODO:
-        // if data lost is not to be retransmitted:
-        //     flightsize = flightsize - bytes_not_to_be_retransmitted
+        self.congestion.on_loss();
     }
 
     fn on_congestion_timeout_expired(&mut self) {
-        // if no ACKs are received within a CTO:
-        // # extreme congestion, or significant RTT change.
-        // # set cwnd to 1MSS and backoff the congestion timer.
-        // cwnd = 1 * MSS
-        self.cwnd = MSS;
+        self.congestion.on_timeout();
         self.congestion_timeout *= 2;
+        self.pmtu.on_timeout();
     }
 
-    fn handle_state(&mut self, packet: PacketRef<'_>) {
+    fn handle_state(&mut self, packet: &PacketRef<'_>) {
         let ack_number = packet.get_ack_number();
-        let acked = self.inflight_packets.iter().find(|p| p.get_seq_number() == ack_number);
-        let ackeds = self.inflight_packets.iter().filter(|p| p.get_seq_number().cmp_less_equal(ack_number));
+        self.pmtu.on_ack_received();
 
-        let nbytes = acked.unwrap().size();
-        println!("NBYTES {:?}", nbytes);
+        // bytes_newly_acked covers every inflight packet this (cumulative)
+        // ack clears, not just the one matching ack_number exactly.
+        // rtt_sample comes from the newest acked packet that was never
+        // retransmitted (Karn's algorithm: a retransmission makes it
+        // ambiguous which transmission the ack is timing).
+        let mut bytes_newly_acked = 0u32;
+        let mut rtt_sample = None;
 
-        let delay = packet.get_timestamp_diff();
-        if !delay.is_zero() {
-            println!("ADDING DELAY {:?}", delay);
-            self.delay_history.add_delay(delay);
+        for p in self.inflight_packets.iter().take_while(|p| p.get_seq_number().cmp_less_equal(ack_number)) {
+            bytes_newly_acked += p.size() as u32;
+
+            if !p.is_retransmission() {
+                rtt_sample = Some(p.get_send_time().elapsed());
+            }
         }
 
-        println!("HISTORY: {:#?}", self.delay_history);
+        if bytes_newly_acked == 0 {
+            // Duplicate or out-of-order ack, nothing new to account for.
+            return;
+        }
 
-        // self.handle_ack(&packet, nbytes);
+        if let Some(rtt_sample) = rtt_sample {
+            self.update_congestion_timeout(rtt_sample);
+        }
 
-        self.inflight_packets.pop_front();
+        self.handle_ack(packet, bytes_newly_acked);
+
+        while self.inflight_packets.front()
+            .map_or(false, |p| p.get_seq_number().cmp_less_equal(ack_number))
+        {
+            self.inflight_packets.pop_front();
+        }
     }
 
-    fn handle_ack(&mut self, packet: &PacketRef<'_>, bytes_newly_acked: usize) {
-        // flightsize is the amount of data outstanding before this ACK
-        //    was received and is updated later;
-        // bytes_newly_acked is the number of bytes that this ACK
-        //    newly acknowledges, and it MAY be set to MSS.
-        println!("BEFORE CWND {:?}", self.cwnd);
+    /// Parse a Selective ACK extension and remove every packet it reports as
+    /// received, even though they're ahead of a hole left by the cumulative
+    /// ack_number. Each SACK ack counts once against every inflight packet
+    /// below its highest sacked seq, however many bits it sets above that
+    /// hole; a hole reported by three or more SACK acks is fast-retransmitted
+    /// instead of waiting for the congestion timeout.
+    async fn handle_sack(&mut self, packet: &PacketRef<'_>) -> Result<()> {
+        let ack_number = packet.get_ack_number();
 
-        let delay = packet.get_timestamp_diff();
-        // self.update_base_delay(delay);
-        // self.update_current_delay(delay);
+        let bitmask = match packet.get_extension(SACK_EXTENSION) {
+            Some(bitmask) => bitmask,
+            None => return Ok(()),
+        };
 
-        // const std::int64_t window_factor = (std::int64_t(acked_bytes) * (1 << 16)) / in_flight;
-	    // const std::int64_t delay_factor = (std::int64_t(target_delay - delay) * (1 << 16)) / target_delay;
+        let mut highest_sacked = ack_number;
 
-        //let window_factor = bytes_newly_acked / self.inflight_size();
-        //let delay_factor = TARGET -
+        for (byte_idx, byte) in bitmask.iter().enumerate() {
+            for bit in 0..8u16 {
+                if byte & (1 << bit) == 0 {
+                    continue;
+                }
 
-        // let queuing_delay = self.filter_current_delays()
-        //     - *self.base_delays.iter().min().unwrap();
-        // let queuing_delay: i64 = queuing_delay.into();
+                let seq = ack_number + 2 + (byte_idx as u16 * 8 + bit);
 
-        // let off_target = (TARGET as f64 - queuing_delay as f64) / TARGET as f64;
+                if let Some(pos) = self.inflight_packets.iter().position(|p| p.get_seq_number() == seq) {
+                    self.inflight_packets.remove(pos);
+                }
 
-        //println!("FILTER {:?}", self.filter_current_delays());
+                self.sack_miss_counts.remove(&seq);
+                highest_sacked = seq;
+            }
+        }
+
+        let missing: Vec<SequenceNumber> = self.inflight_packets
+            .iter()
+            .filter(|p| p.get_seq_number().cmp_less(highest_sacked))
+            .map(Packet::get_seq_number)
+            .collect();
 
-        // TODO: Compute bytes_newly_acked;
-        //let bytes_newly_acked = 61;
+        for seq in missing {
+            let count = self.sack_miss_counts.entry(seq).or_insert(0);
+            *count += 1;
+
+            if *count >= SACK_FAST_RETRANSMIT_THRESHOLD {
+                self.fast_retransmit(seq).await?;
+                self.sack_miss_counts.remove(&seq);
+            }
+        }
 
-        // let cwnd = self.cwnd as f64 + ((GAIN as f64 * off_target as f64 * bytes_newly_acked as f64 * MSS as f64) / self.cwnd as f64);
-        // let max_allowed_cwnd = self.inflight_size() + (ALLOWED_INCREASE * MSS) as usize;
+        Ok(())
+    }
 
-        // println!("CWND {:?} MAX_ALLOWED {:?}", cwnd, max_allowed_cwnd);
+    /// Resend a single hole immediately rather than waiting for the CTO, and
+    /// account for the loss at most once per RTT.
+    async fn fast_retransmit(&mut self, seq: SequenceNumber) -> Result<()> {
+        if let Some(pos) = self.inflight_packets.iter().position(|p| p.get_seq_number() == seq) {
+            self.inflight_packets[pos].set_retransmission(true);
+            self.inflight_packets[pos].update_timestamp();
 
-        // let cwnd = (cwnd as u32).min(max_allowed_cwnd as u32);
+            let bytes = self.inflight_packets[pos].as_bytes().to_vec();
+            self.udp.send(&bytes).await?;
 
-        // println!("DELAY {:?} QUEUING_DELAY {:?} OFF_TARGET {:?}", delay, queuing_delay, off_target);
+            self.inflight_packets[pos].set_send_time(Instant::now());
+        }
 
-        // self.cwnd = cwnd.max(MIN_CWND * MSS);
+        let rtt = self.srtt.unwrap_or(self.congestion_timeout);
+        let should_signal_loss = self.last_loss_signal.map_or(true, |t| t.elapsed() >= rtt);
 
-        // println!("FINAL CWND {:?}", self.cwnd);
-        //self.flight_size -= bytes_newly_acked;
+        if should_signal_loss {
+            self.on_data_loss();
+            self.last_loss_signal = Some(Instant::now());
+        }
 
-//        let cwnd = std::cmp::min(cwnd, max_allowed_cwnd);
+        Ok(())
+    }
 
-       // for each delay sample in the acknowledgement:
-       //     delay = acknowledgement.delay
-       //     update_base_delay(delay)
-       //     update_current_delay(delay)
+    fn handle_ack(&mut self, packet: &PacketRef<'_>, bytes_newly_acked: u32) {
+        let delay = packet.get_timestamp_diff();
+        let inflight = self.inflight_size() as u32;
 
-       // queuing_delay = FILTER(current_delays) - MIN(base_delays)
-       // off_target = (TARGET - queuing_delay) / TARGET
-       // cwnd += GAIN * off_target * bytes_newly_acked * MSS / cwnd
-       // max_allowed_cwnd = flightsize + ALLOWED_INCREASE * MSS
-       // cwnd = min(cwnd, max_allowed_cwnd)
-       // cwnd = max(cwnd, MIN_CWND * MSS)
-       // flightsize = flightsize - bytes_newly_acked
-       // update_CTO()
+        self.congestion.on_ack(bytes_newly_acked, inflight, delay);
     }
 
-    fn update_congestion_timeout(&mut self) {
-        // TODO
+    /// A State packet may echo back the id of an MTU probe the peer
+    /// received, confirming that candidate size made it through intact.
+    fn handle_probe_ack(&mut self, packet: &PacketRef<'_>) {
+        if let Some(id_bytes) = packet.get_extension(MTU_PROBE_EXTENSION) {
+            if let Ok(id_bytes) = <[u8; 4]>::try_from(id_bytes) {
+                self.pmtu.on_probe_acked(u32::from_be_bytes(id_bytes));
+            }
+        }
+    }
+
+    /// RFC 6298: derive the congestion timeout from a fresh RTT sample.
+    fn update_congestion_timeout(&mut self, rtt_sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt_sample);
+                self.rttvar = rtt_sample / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt_sample { srtt - rtt_sample } else { rtt_sample - srtt };
+                self.rttvar = self.rttvar.mul_f64(0.75) + delta.mul_f64(0.25);
+                self.srtt = Some(srtt.mul_f64(0.875) + rtt_sample.mul_f64(0.125));
+            }
+        }
+
+        let srtt = self.srtt.expect("srtt was just seeded above");
+        let timeout = srtt + CLOCK_GRANULARITY.max(self.rttvar * 4);
+
+        self.congestion_timeout = timeout.clamp(MIN_CONGESTION_TIMEOUT, MAX_CONGESTION_TIMEOUT);
     }
 
     async fn send_ack(&mut self) -> Result<()> {
@@ -491,8 +948,466 @@ impl UtpSocket {
         header.set_connection_id(self.send_id);
         header.set_seq_number(self.seq_number);
         header.set_ack_number(self.ack_number);
+        header.set_window_size(self.advertised_window());
         self.seq_number += 1;
 
+        if !self.out_of_order_seqs.is_empty() {
+            let bitmask = self.build_sack_bitmask();
+            header.set_extension(SACK_EXTENSION, &bitmask);
+        }
+
+        if let Some(probe_id) = self.pending_probe_ack {
+            header.set_extension(MTU_PROBE_EXTENSION, &probe_id.to_be_bytes());
+        }
+
+        header.update_timestamp();
+        self.udp.send(header.as_bytes()).await?;
+
         Ok(())
     }
+
+    /// Bit k of the mask refers to seq number `ack_number + 2 + k`. Distances
+    /// are wrapping u16 subtractions, like every other seq comparison in this
+    /// file (`cmp_less`/`cmp_less_equal`) — `ack_number` wraps on any
+    /// long-lived connection and a plain `-` would panic or underflow past it.
+    fn build_sack_bitmask(&self) -> Vec<u8> {
+        let base = self.ack_number + 2;
+        let highest = self.out_of_order_seqs.iter().copied()
+            .filter(|&seq| !seq.cmp_less(base))
+            .max_by_key(|&seq| u16::from(seq).wrapping_sub(u16::from(base)))
+            .unwrap_or(base);
+        let span = usize::from(u16::from(highest).wrapping_sub(u16::from(base))) + 1;
+        let mut bitmask = vec![0u8; (span + 7) / 8];
+
+        for &seq in &self.out_of_order_seqs {
+            if seq.cmp_less(base) {
+                continue;
+            }
+
+            let k = usize::from(u16::from(seq).wrapping_sub(u16::from(base)));
+            bitmask[k / 8] |= 1 << (k % 8);
+        }
+
+        bitmask
+    }
+}
+
+/// Bind a fresh socket to `addr` with `SO_REUSEADDR` (and, on unix,
+/// `SO_REUSEPORT`) set, so it can share the listener's exact address. The OS
+/// still demuxes by the full 4-tuple, so once this socket `connect()`s to a
+/// specific peer, that peer's traffic routes to it instead of the listener.
+fn bind_reuse(addr: SocketAddr) -> Result<UdpSocket> {
+    let domain = if addr.is_ipv4() { Domain::IPV4 } else { Domain::IPV6 };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+
+    socket.bind(&addr.into())?;
+
+    Ok(UdpSocket::from(std::net::UdpSocket::from(socket)))
+}
+
+/// Accepts incoming uTP connections on a bound UDP socket.
+///
+/// A uTP connection is identified by the peer's (address, connection id)
+/// pair, not by the usual 4-tuple the listening socket sees. Once a SYN
+/// opens a connection we hand it its own socket connected to that specific
+/// peer, which narrows its 4-tuple so the OS routes the rest of that
+/// conversation straight to it instead of back through the listener.
+pub struct UtpListener {
+    udp: UdpSocket,
+    local: SocketAddr,
+
+    /// (connection id, peer address) pairs that have already completed a
+    /// handoff to their own dedicated socket. Unlike a guard that's
+    /// inserted and removed within a single `accept()` call, this persists
+    /// across calls, so a SYN the peer retransmits after the handoff (its
+    /// own retry timer racing our State reply, say) is recognized here and
+    /// dropped instead of starting a second, conflicting socket for a peer
+    /// that's already connected.
+    established: HashSet<(ConnectionId, SocketAddr)>,
+}
+
+impl UtpListener {
+    pub async fn bind(addr: SocketAddr) -> Result<UtpListener> {
+        // Bound with the same reuse flags as the per-peer sockets, so that
+        // `handoff` can bind them to this exact address.
+        let udp = bind_reuse(addr)?;
+        let local = udp.local_addr()?;
+
+        Ok(UtpListener {
+            local,
+            udp,
+            established: HashSet::new(),
+        })
+    }
+
+    /// Wait for and complete the next inbound handshake, returning a
+    /// connected `UtpSocket`. A SYN for a connection that has already been
+    /// handed off is dropped here rather than starting a second handshake.
+    pub async fn accept(&mut self) -> Result<UtpSocket> {
+        let mut buffer = [0; 1500];
+
+        loop {
+            let (len, remote) = self.udp.recv_from(&mut buffer).await?;
+            let packet = PacketRef::ref_from_buffer(&buffer[..len])?;
+
+            if packet.get_type()? != PacketType::Syn {
+                // Anything else reaching the listener is either stale or
+                // belongs to a peer whose dedicated socket hasn't taken
+                // over the 4-tuple yet; either way there's nothing to do.
+                continue;
+            }
+
+            let connection_id = packet.get_connection_id();
+
+            if self.established.contains(&(connection_id, remote)) {
+                continue;
+            }
+
+            let seq_number = packet.get_seq_number();
+            let result = Self::handoff(self.local, connection_id, remote, seq_number).await;
+
+            // Only mark the connection established once its dedicated
+            // socket actually exists; a failed handoff should still let a
+            // retried SYN get a fresh attempt rather than being dropped
+            // forever.
+            if result.is_ok() {
+                self.established.insert((connection_id, remote));
+            }
+
+            return result;
+        }
+    }
+
+    /// Bind a dedicated socket for `remote`, connect it to narrow the
+    /// 4-tuple, and complete the passive open with a State reply.
+    async fn handoff(
+        local: SocketAddr,
+        connection_id: ConnectionId,
+        remote: SocketAddr,
+        peer_seq_number: SequenceNumber,
+    ) -> Result<UtpSocket> {
+        let peer_udp = bind_reuse(local)?;
+        peer_udp.connect(remote).await?;
+
+        let mut socket = UtpSocket::new(local, peer_udp);
+        socket.remote = Some(remote);
+        socket.recv_id = connection_id + 1;
+        socket.send_id = connection_id;
+        socket.seq_number = SequenceNumber::random();
+        socket.ack_number = peer_seq_number;
+        socket.state = State::Connected;
+
+        socket.send_ack().await?;
+
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_with(ack_number: SequenceNumber, out_of_order_seqs: Vec<SequenceNumber>) -> UtpSocket {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let udp = async_std::task::block_on(UdpSocket::bind(local)).unwrap();
+
+        let mut socket = UtpSocket::new(local, udp);
+        socket.ack_number = ack_number;
+        socket.out_of_order_seqs = out_of_order_seqs;
+        socket
+    }
+
+    #[test]
+    fn build_sack_bitmask_marks_out_of_order_seqs() {
+        let base = SequenceNumber::zero() + 2u16;
+        let socket = socket_with(SequenceNumber::zero(), vec![base, base + 3u16, base + 5u16]);
+
+        let bitmask = socket.build_sack_bitmask();
+
+        assert_eq!(bitmask.len(), 1);
+        for bit in [0u32, 3, 5] {
+            assert_ne!(bitmask[0] & (1 << bit), 0, "bit {bit} should be set");
+        }
+        for bit in [1u32, 2, 4] {
+            assert_eq!(bitmask[0] & (1 << bit), 0, "bit {bit} should be clear");
+        }
+    }
+
+    #[test]
+    fn build_sack_bitmask_handles_ack_number_wraparound() {
+        // ack_number sits right before the u16 wrap, so base = ack_number + 2 is u16::MAX,
+        // the later seqs wrap past 0, and a plain `highest - base` subtraction would underflow.
+        let ack_number = SequenceNumber::zero() + (u16::MAX - 2);
+        let base = ack_number + 2u16;
+        let socket = socket_with(ack_number, vec![base, base + 1u16, base + 2u16]);
+
+        let bitmask = socket.build_sack_bitmask();
+
+        assert_eq!(bitmask.len(), 1);
+        for bit in [0u32, 1, 2] {
+            assert_ne!(bitmask[0] & (1 << bit), 0, "bit {bit} should be set");
+        }
+    }
+
+    #[test]
+    fn ledbat_on_ack_grows_cwnd_when_under_target_delay() {
+        let mut ledbat = Ledbat::new();
+        let initial_cwnd = ledbat.window();
+
+        // A zero delay reading means no queuing delay above the (also zero,
+        // freshly-seeded) base delay, so off_target is at its most positive
+        // and cwnd should grow; inflight is set high enough that
+        // max_allowed_cwnd doesn't clamp the result.
+        ledbat.on_ack(MSS, initial_cwnd, Delay::default());
+
+        assert!(ledbat.window() > initial_cwnd);
+    }
+
+    #[test]
+    fn ledbat_on_loss_halves_cwnd_but_not_below_floor() {
+        let mut ledbat = Ledbat::new();
+        let before = ledbat.window();
+
+        ledbat.on_loss();
+
+        assert_eq!(ledbat.window(), (before / 2).max(MIN_CWND * MSS));
+    }
+
+    #[test]
+    fn ledbat_on_timeout_resets_cwnd_to_one_segment() {
+        let mut ledbat = Ledbat::new();
+
+        ledbat.on_timeout();
+
+        assert_eq!(ledbat.window(), MSS);
+    }
+
+    #[test]
+    fn update_congestion_timeout_clamps_tiny_rtt_to_minimum() {
+        let mut socket = socket_with(SequenceNumber::zero(), Vec::new());
+
+        // A 1ms RTT sample computes to 1ms + CLOCK_GRANULARITY = 101ms,
+        // which the clamp raises to MIN_CONGESTION_TIMEOUT.
+        socket.update_congestion_timeout(Duration::from_millis(1));
+
+        assert_eq!(socket.congestion_timeout, MIN_CONGESTION_TIMEOUT);
+    }
+
+    #[test]
+    fn update_congestion_timeout_follows_rfc6298_on_first_sample() {
+        let mut socket = socket_with(SequenceNumber::zero(), Vec::new());
+
+        // First sample seeds srtt = sample, rttvar = sample / 2, per RFC 6298.
+        socket.update_congestion_timeout(Duration::from_millis(200));
+
+        assert_eq!(socket.srtt, Some(Duration::from_millis(200)));
+        assert_eq!(socket.rttvar, Duration::from_millis(100));
+        // timeout = srtt + max(CLOCK_GRANULARITY, rttvar * 4) = 200ms + 400ms
+        assert_eq!(socket.congestion_timeout, Duration::from_millis(600));
+    }
+
+    /// A socket whose udp is connected to a throwaway local peer, so
+    /// `retransmit_oldest`'s real `udp.send` has somewhere to go.
+    fn connected_socket() -> UtpSocket {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let peer: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let udp = async_std::task::block_on(UdpSocket::bind(local)).unwrap();
+        let peer_udp = async_std::task::block_on(UdpSocket::bind(peer)).unwrap();
+        let peer_addr = peer_udp.local_addr().unwrap();
+        async_std::task::block_on(udp.connect(peer_addr)).unwrap();
+
+        UtpSocket::new(local, udp)
+    }
+
+    #[test]
+    fn retransmit_oldest_is_a_noop_when_nothing_is_inflight() {
+        let mut socket = connected_socket();
+        let timeout_before = socket.congestion_timeout;
+
+        async_std::task::block_on(socket.retransmit_oldest()).unwrap();
+
+        // Nothing inflight means the timeout was stale, so the congestion
+        // response (which would double congestion_timeout) never fires.
+        assert_eq!(socket.congestion_timeout, timeout_before);
+    }
+
+    #[test]
+    fn retransmit_oldest_bumps_retries_until_the_cap() {
+        let mut socket = connected_socket();
+        let mut packet = Packet::new(&[0u8; 10]);
+        packet.set_retries(MAX_RETRANSMISSION_RETRIES - 1);
+        socket.inflight_packets.push_back(packet);
+
+        async_std::task::block_on(socket.retransmit_oldest()).unwrap();
+
+        let retried = socket.inflight_packets.front().unwrap();
+        assert_eq!(retried.get_retries(), MAX_RETRANSMISSION_RETRIES);
+        assert!(retried.is_retransmission());
+    }
+
+    #[test]
+    fn retransmit_oldest_gives_up_past_max_retries() {
+        let mut socket = connected_socket();
+        let mut packet = Packet::new(&[0u8; 10]);
+        packet.set_retries(MAX_RETRANSMISSION_RETRIES);
+        socket.inflight_packets.push_back(packet);
+
+        let result = async_std::task::block_on(socket.retransmit_oldest());
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn handle_data_holds_out_of_order_then_drains_on_gap_fill() {
+        let mut socket = socket_with(SequenceNumber::zero(), Vec::new());
+
+        let seq1 = socket.ack_number + 1u16;
+        let seq2 = socket.ack_number + 2u16;
+
+        // seq2 arrives before seq1: it's held back, nothing delivered yet.
+        let mut packet2 = Packet::new(b"world");
+        packet2.set_seq_number(seq2);
+        packet2.set_ack_number(socket.ack_number);
+        packet2.update_timestamp();
+        let bytes2 = packet2.as_bytes().to_vec();
+        let packet_ref2 = PacketRef::ref_from_buffer(&bytes2).unwrap();
+        socket.handle_data(&packet_ref2);
+
+        assert!(socket.recv_buffer.is_empty());
+        assert_eq!(socket.out_of_order_seqs, vec![seq2]);
+
+        // seq1 fills the gap; both segments should land in recv_buffer in order.
+        let mut packet1 = Packet::new(b"hello");
+        packet1.set_seq_number(seq1);
+        packet1.set_ack_number(socket.ack_number);
+        packet1.update_timestamp();
+        let bytes1 = packet1.as_bytes().to_vec();
+        let packet_ref1 = PacketRef::ref_from_buffer(&bytes1).unwrap();
+        socket.handle_data(&packet_ref1);
+
+        let received: Vec<u8> = socket.recv_buffer.iter().copied().collect();
+        assert_eq!(received, b"helloworld".to_vec());
+        assert_eq!(socket.ack_number, seq2);
+        assert!(socket.out_of_order_seqs.is_empty());
+    }
+
+    #[test]
+    fn listener_handoff_produces_a_connected_socket() {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let remote: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let (connection_id, _) = ConnectionId::make_ids();
+        let peer_seq = SequenceNumber::zero() + 42u16;
+
+        let socket = async_std::task::block_on(
+            UtpListener::handoff(local, connection_id, remote, peer_seq)
+        ).unwrap();
+
+        assert_eq!(socket.state, State::Connected);
+        assert_eq!(socket.remote, Some(remote));
+        assert!(socket.send_id == connection_id);
+        assert!(socket.recv_id == connection_id + 1);
+        assert_eq!(socket.ack_number, peer_seq);
+    }
+
+    #[test]
+    fn recv_drains_buffered_bytes_without_touching_the_wire() {
+        let mut socket = socket_with(SequenceNumber::zero(), Vec::new());
+        socket.state = State::Connected;
+        socket.recv_buffer.extend(b"hello".iter().copied());
+
+        let mut buf = [0u8; 3];
+        let n = async_std::task::block_on(socket.recv(&mut buf)).unwrap();
+
+        assert_eq!(n, 3);
+        assert_eq!(&buf, b"hel");
+
+        let remaining: Vec<u8> = socket.recv_buffer.iter().copied().collect();
+        assert_eq!(remaining, b"lo".to_vec());
+    }
+
+    #[test]
+    fn cubic_seeds_growth_epoch_at_construction() {
+        let cubic = Cubic::new();
+
+        // Before the fix, `last_loss` was `None` until a first `on_loss` /
+        // `on_timeout`, freezing `on_ack`'s `t` at 0 forever. It's now a
+        // plain `Instant` seeded right here, so growth starts immediately.
+        assert!(cubic.last_loss.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn cubic_on_ack_grows_over_time_without_a_prior_loss() {
+        let mut cubic = Cubic::new();
+        // Pretend cwnd already grew past the MIN_CWND floor, so later growth
+        // isn't masked by clamping.
+        cubic.cwnd = 10 * MSS;
+        cubic.w_max = 10 * MSS;
+
+        cubic.on_ack(MSS, cubic.cwnd, Delay::default());
+        let first = cubic.window();
+
+        std::thread::sleep(Duration::from_millis(50));
+        cubic.on_ack(MSS, first, Delay::default());
+        let second = cubic.window();
+
+        assert!(second > first, "cwnd should keep growing across calls without needing a loss first");
+    }
+
+    #[test]
+    fn pmtu_should_probe_respects_outstanding_probe_and_interval() {
+        let mut pmtu = PmtuDiscovery::new(2000);
+        let t0 = Instant::now();
+
+        assert!(pmtu.should_probe(t0));
+
+        pmtu.probe_sent(1400, 1, t0);
+        assert!(!pmtu.should_probe(t0), "shouldn't probe again while one is outstanding");
+
+        pmtu.on_probe_acked(1);
+        assert!(!pmtu.should_probe(t0 + Duration::from_secs(1)), "too soon since the last probe");
+        assert!(pmtu.should_probe(t0 + PMTU_PROBE_INTERVAL));
+    }
+
+    #[test]
+    fn pmtu_check_probe_timeout_resends_up_to_the_cap_then_gives_up() {
+        let mut pmtu = PmtuDiscovery::new(2000);
+        let t0 = Instant::now();
+        let size = pmtu.next_probe_size();
+        let id = pmtu.next_probe_id();
+        pmtu.probe_sent(size, id, t0);
+
+        assert_eq!(pmtu.check_probe_timeout(t0), None, "not timed out yet");
+
+        let mut t = t0;
+        for attempt in 1..PMTU_PROBE_MAX_RETRIES {
+            t += PMTU_PROBE_TIMEOUT;
+            let resend = pmtu.check_probe_timeout(t);
+            assert_eq!(resend, Some((size, id)), "attempt {attempt} should resend, not give up");
+        }
+
+        // One more timeout past the retry cap gives up and caps the ceiling
+        // just below the size that never got through.
+        t += PMTU_PROBE_TIMEOUT;
+        assert_eq!(pmtu.check_probe_timeout(t), None);
+        assert_eq!(pmtu.ceiling, size - 1);
+    }
+
+    #[test]
+    fn pmtu_on_timeout_resets_to_base_after_blackhole_threshold() {
+        let mut pmtu = PmtuDiscovery::new(2000);
+        pmtu.current = 1800;
+
+        for _ in 0..PMTU_BLACKHOLE_THRESHOLD - 1 {
+            pmtu.on_timeout();
+            assert_eq!(pmtu.current, 1800, "shouldn't reset before the threshold");
+        }
+
+        pmtu.on_timeout();
+        assert_eq!(pmtu.current, PMTU_BASE.min(2000));
+    }
 }